@@ -75,29 +75,106 @@
  *  And last, the scheduler continues the scheduling loop and selects a proper coroutine to wake up.
  */
 
+use std::any::Any;
+use std::collections::VecDeque;
 use std::default::Default;
 use std::mem::transmute;
+use std::panic;
 //use std::rt::unwind::try;
-use std::cell::UnsafeCell;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::future::Future;
 use std::ops::Deref;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::fmt::{self, Debug};
 use std::sync::Mutex;
+use std::task::{Context as TaskContext, Poll, RawWaker, RawWakerVtable, Waker};
 
 
 use context::Context;
 use context::stack::Stack;
 use {Options, Result, Error};
 
-pub struct State;
+/// The marker payload used to unwind a cancelled Coroutine's stack.
+///
+/// `Coroutine::main` recognises this payload and swallows it instead of letting it escape
+/// into the parent/scheduler, the same way a normal `panic!` inside the Coroutine never does.
+struct Cancelled;
+
+/// Execution state of a `Coroutine`.
+///
+/// A Coroutine starts out `Running` (which also covers "runnable but currently parked at a
+/// yield point"). `Handle::cancel()` moves it to `Cancelling`, which the Coroutine turns into
+/// an unwind at its next yield point outside an uninterruptible region. Once the body has
+/// returned (or been unwound by a cancellation), the Coroutine is `Completed`; if the body
+/// panicked it is `Panicked`. Either way it can never be resumed again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    /// Runnable, or suspended at a yield point waiting to be resumed.
+    Running,
+    /// Parked waiting on something external (a `block()` caller, or a `Future` that returned
+    /// `Pending`); only resumed again once that something wakes it.
+    Blocked,
+    /// A cancellation has been requested; the next time this Coroutine passes a yield point
+    /// outside an uninterruptible region it will unwind.
+    Cancelling,
+    /// The Coroutine's body ran to completion normally, or its cancellation unwind finished.
+    Completed,
+    /// The Coroutine's body panicked (a genuine application panic, not a cancellation). The
+    /// payload is available through `Handle::join()`.
+    Panicked,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::Running
+    }
+}
+
+/// A type whose value can be run as the body of a Coroutine, via `Coroutine::spawn_as`.
+///
+/// This lets callers attach their own state to a Coroutine's body (as fields on `Self`)
+/// instead of capturing everything in a closure, and lets them override the Coroutine's name
+/// and stack on a per-type basis rather than only per-call through `Options`.
+pub trait Coroutinable: Send + 'static {
+    /// Run this value's logic as the Coroutine's body.
+    fn run(self);
+
+    /// Name given to the spawned Coroutine unless this is overridden. Defaults to the type's
+    /// name.
+    fn name(&self) -> Option<String> {
+        Some(::std::any::type_name::<Self>().to_string())
+    }
+
+    /// Preferred stack size in bytes, used only if `stack()` returns `None`. Defaults to
+    /// `Options::default()`'s stack size.
+    fn stack_size(&self) -> usize {
+        Options::default().stack_size
+    }
+
+    /// Supply a pre-allocated `Stack` to run on instead of letting `spawn_as` heap-allocate a
+    /// fresh one sized by `stack_size()`. Lets embedders reuse stacks across coroutines.
+    fn stack(&self) -> Option<Stack> {
+        None
+    }
+}
+
+thread_local!(static CURRENT_COROUTINE: UnsafeCell<Option<Handle>> = UnsafeCell::new(None));
+
+/// The `WakeSink` installed by whichever `Scheduler` is currently resuming a Coroutine on this
+/// thread, if any. `Coroutine::await_future` reads this when it builds a `Waker` so that, once
+/// that `Waker` fires, it routes the woken `Handle` back through the `Scheduler` that owns it
+/// instead of resuming the Handle inline from whatever thread the wake happened to come from.
+thread_local!(static CURRENT_WAKE_SINK: RefCell<Option<WakeSink>> = RefCell::new(None));
 
 /// Handle of a Coroutine
 #[derive(Clone)]
-pub struct Handle;
-pub static HANDLE : Handle = Handle;
+pub struct Handle(Arc<UnsafeCell<Coroutine>>);
+
 impl Debug for Handle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-      Ok(())
+        let coro = unsafe { &*self.0.get() };
+        write!(f, "Handle {{ name: {:?}, state: {:?} }}", coro.name, *coro.state.lock().unwrap())
     }
 }
 
@@ -106,20 +183,46 @@ unsafe impl Sync for Handle {}
 
 impl Handle {
     fn new(c: Coroutine) -> Handle {
-        Handle
+        Handle(Arc::new(UnsafeCell::new(c)))
     }
 
-
-
     /// Resume the Coroutine
     pub fn resume(&self) -> Result<State> {
-          Ok(State)
+        let coro = unsafe { &mut *self.0.get() };
+
+        match *coro.state.lock().unwrap() {
+            State::Completed => {
+                return Err(Error::Panicked("cannot resume a Coroutine that has already completed".to_string()));
+            }
+            State::Panicked => {
+                return Err(Error::Panicked("cannot resume a Coroutine that has already panicked".to_string()));
+            }
+            _ => {}
+        }
+
+        let prev = CURRENT_COROUTINE.with(|c| unsafe { (*c.get()).take() });
+        CURRENT_COROUTINE.with(|c| unsafe { *c.get() = Some(self.clone()) });
+
+        let mut caller_ctx = Context::empty();
+        coro.parent_context = &mut caller_ctx as *mut Context;
+
+        unsafe {
+            Context::swap(&mut caller_ctx, &coro.saved_context, 0);
+        }
+
+        CURRENT_COROUTINE.with(|c| unsafe { *c.get() = prev });
+
+        Ok(*coro.state.lock().unwrap())
     }
 
     /// Join this Coroutine.
     ///
     /// If the Coroutine panicked, this method will return an `Err` with panic message.
     ///
+    /// Because `Coroutine::main` only transitions to `Completed` once every child spawned
+    /// with `spawn_child`/`spawn_scoped` has itself finished (`Completed` or `Panicked`), joining a Coroutine
+    /// transitively waits for its whole subtree of children too.
+    ///
     /// ```
     /// use coroutine::Coroutine;
     /// use coroutine::sched;
@@ -130,22 +233,87 @@ impl Handle {
     ///     println!("Exiting");
     /// }).join().unwrap();
     /// ```
+    ///
+    /// A child that is `Blocked` (e.g. parked in `await_future`, or simply looping on
+    /// `Coroutine::block()`) is not registered on any `Scheduler`'s ready queue when it was
+    /// spawned via `spawn_child`/`spawn_scoped` -- this `join()` call may be the only thing that
+    /// will ever resume it. So this polls `resume()` rather than parking the calling thread on
+    /// anything: a condvar-wait here would have nothing left to wake it, since `resume()` is
+    /// also the only thing that could ever satisfy that wait.
     #[inline]
     pub fn join(&self) -> Result<State> {
-        Ok(State)
+        let coro = unsafe { &*self.0.get() };
+        loop {
+            // Snapshot the state before resuming: if some other thread's `resume()` (e.g. a
+            // `Scheduler` driving it, or a waker firing) already ran this Coroutine to a
+            // terminal state since our last poll, use that directly. Calling `resume()`
+            // ourselves on an already-terminal Coroutine would hit its own `Completed`/
+            // `Panicked` guard and turn a successful completion into a spurious `Err`.
+            let snapshot = *coro.state.lock().unwrap();
+            let state = match snapshot {
+                State::Completed | State::Panicked => snapshot,
+                _ => try!(self.resume()),
+            };
+
+            match state {
+                State::Completed => return Ok(State::Completed),
+                State::Panicked => {
+                    let payload = coro.panic_payload.lock().unwrap().take();
+                    return Err(Error::Panicked(panic_message(payload)));
+                }
+                _ => {}
+            }
+        }
     }
 
     /// Get the state of the Coroutine
     #[inline]
     pub fn state(&self) -> State {
-    State
+        let coro = unsafe { &*self.0.get() };
+        *coro.state.lock().unwrap()
     }
 
-    /// Set the state of the Coroutine
-    #[inline]
-    fn set_state(&self, state: State) {
+    /// Request cancellation of this Coroutine.
+    ///
+    /// This does not unwind the Coroutine's stack immediately. Instead it records the request
+    /// and the next time the Coroutine reaches a yield point (inside `yield_now`/`sched`/
+    /// `block`), it panics into its own unwind path so that every stack-allocated destructor
+    /// between that point and `Coroutine::main` runs before the Coroutine is dropped.
+    ///
+    /// Calling `cancel()` on a Coroutine that is already `Completed` or already `Cancelling`
+    /// is a no-op.
+    pub fn cancel(&self) {
+        let coro = unsafe { &*self.0.get() };
+        coro.into_cancelling();
     }
 
+    /// Obtain an RAII guard that suppresses cancellation of this Coroutine until it is dropped.
+    ///
+    /// While one or more guards are alive, a concurrent `cancel()` only records the request;
+    /// the actual unwind is deferred until the last guard drops and control reaches the
+    /// Coroutine's next yield point. This keeps a stack-local buffer handed to an in-flight
+    /// asynchronous operation alive until the operation is drained, rather than being unwound
+    /// out from under it.
+    pub fn uninterruptible_guard(&self) -> UninterruptibleGuard {
+        let coro = unsafe { &*self.0.get() };
+        coro.uninterruptible_depth.set(coro.uninterruptible_depth.get() + 1);
+        UninterruptibleGuard { handle: self.clone() }
+    }
+}
+
+/// RAII guard returned by `Handle::uninterruptible_guard()`.
+///
+/// Holding this guard defers any pending cancellation of the Coroutine that created it until
+/// the guard is dropped.
+pub struct UninterruptibleGuard {
+    handle: Handle,
+}
+
+impl Drop for UninterruptibleGuard {
+    fn drop(&mut self) {
+        let coro = unsafe { &*self.handle.0.get() };
+        coro.uninterruptible_depth.set(coro.uninterruptible_depth.get() - 1);
+    }
 }
 
 
@@ -160,9 +328,37 @@ pub struct Coroutine {
     /// Always valid if the task is alive and not running.
     saved_context: Context,
 
+    /// Points at the context that resumed this Coroutine, valid only while it is running.
+    /// Used by `yield_now` to swap control back to whoever called `resume()`.
+    parent_context: *mut Context,
+
+    /// Raw pointer to the boxed closure that is the Coroutine's body, consumed by
+    /// `coroutine_entry` the first time the Coroutine runs. Zero once consumed.
+    entry_data: usize,
+
+    /// Nesting depth of `Coroutine::uninterruptible` regions / live `UninterruptibleGuard`s.
+    /// While non-zero, a pending `Cancelling` state is not turned into an unwind.
+    uninterruptible_depth: Cell<u32>,
+
     /// State
     state: Mutex<State>,
 
+    /// Child coroutines spawned via `spawn_child`/`spawn_scoped` while this Coroutine was
+    /// running. `Coroutine::main` drives every one of these to `Completed` or `Panicked`
+    /// before it lets this Coroutine itself finish.
+    children: Mutex<Vec<Handle>>,
+
+    /// Set by the panic hook for the duration of an in-flight application panic (from just
+    /// before the stack starts unwinding until `Coroutine::main` catches it), so that `Drop`
+    /// impls running during that window can observe `panicking()` truthfully. A cancellation
+    /// unwind bypasses the panic hook, so `yield_now` sets this flag itself just before it
+    /// calls `panic::resume_unwind`.
+    unwinding: Cell<bool>,
+
+    /// The panic payload captured at `Coroutine::main`'s `catch_unwind` boundary, if the body
+    /// panicked. Taken by `Handle::join()`.
+    panic_payload: Mutex<Option<Box<dyn Any + Send>>>,
+
     /// Name
     name: Option<String>,
 }
@@ -174,44 +370,236 @@ impl Coroutine {
 
     #[doc(hidden)]
     pub unsafe fn empty(name: Option<String>, state: State) -> Handle {
-        Handle
+        Handle::new(Coroutine {
+            current_stack_segment: None,
+            saved_context: Context::empty(),
+            parent_context: ::std::ptr::null_mut(),
+            entry_data: 0,
+            uninterruptible_depth: Cell::new(0),
+            state: Mutex::new(state),
+            children: Mutex::new(Vec::new()),
+            unwinding: Cell::new(false),
+            panic_payload: Mutex::new(None),
+            name: name,
+        })
     }
 
     #[doc(hidden)]
     pub fn new(name: Option<String>, stack: Stack, ctx: Context, state: State) -> Handle {
-        Handle
+        Handle::new(Coroutine {
+            current_stack_segment: Some(stack),
+            saved_context: ctx,
+            parent_context: ::std::ptr::null_mut(),
+            entry_data: 0,
+            uninterruptible_depth: Cell::new(0),
+            state: Mutex::new(state),
+            children: Mutex::new(Vec::new()),
+            unwinding: Cell::new(false),
+            panic_payload: Mutex::new(None),
+            name: name,
+        })
     }
 
     /// Spawn a Coroutine with options
     pub fn spawn_opts<F>(f: F, opts: Options) -> Handle
         where F: FnOnce() + Send + 'static
     {
-      Handle
+        let stack = Stack::new(opts.stack_size);
+        Coroutine::build(opts.name, stack, f)
+    }
+
+    /// Build a Handle around a fresh `saved_context` that will run `f` the first time it is
+    /// resumed, on `stack`. Shared by `spawn_opts` and `spawn_as`, which differ only in how
+    /// they come up with the name, stack, and closure.
+    fn build<F>(name: Option<String>, stack: Stack, f: F) -> Handle
+        where F: FnOnce() + Send + 'static
+    {
+        let ctx = Context::new(&stack, coroutine_entry::<F>);
+
+        let handle = Coroutine::new(name, stack, ctx, State::Running);
 
+        // Stash the closure so the trampoline can pick it up the first time it runs.
+        let coro = unsafe { &mut *handle.0.get() };
+        coro.entry_data = Box::into_raw(Box::new(f)) as *mut u8 as usize;
+
+        handle
+    }
+
+    /// Spawn a Coroutine whose body is `t.run()`.
+    ///
+    /// This generalizes the closure-only `spawn_opts` into a trait-object-friendly interface:
+    /// `t` can carry its own state alongside the logic it runs, and its `Coroutinable::name`/
+    /// `stack_size`/`stack` override the usual `Options` defaults, letting an embedder supply
+    /// a pre-allocated `Stack` instead of always heap-allocating one.
+    pub fn spawn_as<T: Coroutinable>(t: T) -> Handle {
+        let name = t.name();
+        let stack = t.stack().unwrap_or_else(|| Stack::new(t.stack_size()));
+        Coroutine::build(name, stack, move || t.run())
     }
 
     /// Spawn a Coroutine with default options
     pub fn spawn<F>(f: F) -> Handle
         where F: FnOnce() + Send + 'static
     {
-      Handle
-    
+        Coroutine::spawn_opts(f, Options::default())
+    }
+
+    /// Spawn a Coroutine as a child of the currently running Coroutine, with default options.
+    ///
+    /// See `spawn_scoped` for the structured-concurrency guarantee this gives.
+    pub fn spawn_child<F>(f: F) -> Handle
+        where F: FnOnce() + Send + 'static
+    {
+        Coroutine::spawn_scoped(f, Options::default())
+    }
+
+    /// Spawn a Coroutine as a child of the currently running Coroutine, with options.
+    ///
+    /// The parent will not reach `Completed` until every child it has spawned this way has
+    /// itself finished, `Completed` or `Panicked` (a child's panic stays on the child's own
+    /// `Handle`): `Coroutine::main` drives the child set to termination as
+    /// part of the parent's own finalization, so `Handle::join()` on the parent transitively
+    /// waits for the whole subtree instead of orphaning coroutines it launched.
+    pub fn spawn_scoped<F>(f: F, opts: Options) -> Handle
+        where F: FnOnce() + Send + 'static
+    {
+        let child = Coroutine::spawn_opts(f, opts);
+
+        let parent = Coroutine::current();
+        let parent_coro = unsafe { &*parent.0.get() };
+        parent_coro.children.lock().unwrap().push(child.clone());
+
+        child
+    }
+
+    /// The entry boundary of every Coroutine's body.
+    ///
+    /// Runs `f` inside `catch_unwind` so that a cancellation unwind (triggered from
+    /// `yield_now`) is caught here and never escapes into the parent/scheduler, drives any
+    /// outstanding children to completion, then marks the Coroutine `Completed` (or
+    /// `Panicked`, keeping the payload, if the body panicked) before handing control back for
+    /// the last time.
+    fn main<F: FnOnce()>(f: F) -> ! {
+        install_panic_hook();
+
+        let handle = Coroutine::current().clone();
+        let coro = unsafe { &mut *handle.0.get() };
+
+        // A `cancel()` made before this Coroutine ever ran sets `Cancelling` before we get
+        // here; honour it by unwinding straight away instead of running `f` to completion.
+        let cancelled_before_start = *coro.state.lock().unwrap() == State::Cancelling;
+        let result = if cancelled_before_start {
+            drop(f);
+            Err(Box::new(Cancelled) as Box<dyn Any + Send>)
+        } else {
+            panic::catch_unwind(panic::AssertUnwindSafe(f))
+        };
+
+        coro.unwinding.set(false);
+
+        // Don't report ourselves as finished while anything we spawned is still parked:
+        // drive every child to termination first so nothing it owns outlives us unreclaimed.
+        loop {
+            let next = coro.children.lock().unwrap().iter()
+                .find(|child| match child.state() {
+                    State::Completed | State::Panicked => false,
+                    _ => true,
+                })
+                .cloned();
+            match next {
+                Some(child) => { let _ = child.join(); }
+                None => break,
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                coro.set_state(State::Completed);
+            }
+            Err(payload) => {
+                if payload.downcast_ref::<Cancelled>().is_some() {
+                    coro.set_state(State::Completed);
+                } else {
+                    *coro.panic_payload.lock().unwrap() = Some(payload);
+                    coro.set_state(State::Panicked);
+                }
+            }
+        }
+
+        loop {
+            unsafe {
+                Context::swap(&mut coro.saved_context, &*coro.parent_context, 0);
+            }
+        }
     }
 
     /// Yield the current running Coroutine to its parent
     #[inline]
     pub fn yield_now(state: State) {
+        let handle = Coroutine::current().clone();
+        let coro = unsafe { &mut *handle.0.get() };
+
+        // A pending `Cancelling` (requested while this Coroutine was running, or deferred by an
+        // uninterruptible region) is never overwritten; outside such a region it fires here.
+        {
+            let mut current = coro.state.lock().unwrap();
+            if *current != State::Cancelling {
+                *current = state;
+            } else if coro.uninterruptible_depth.get() == 0 {
+                drop(current);
+                coro.unwinding.set(true);
+                panic::resume_unwind(Box::new(Cancelled));
+            }
+        }
+
+        unsafe {
+            Context::swap(&mut coro.saved_context, &*coro.parent_context, 0);
+        }
+
+        let cancelling = *coro.state.lock().unwrap() == State::Cancelling;
+        if cancelling && coro.uninterruptible_depth.get() == 0 {
+            coro.unwinding.set(true);
+            panic::resume_unwind(Box::new(Cancelled));
+        }
     }
 
 
     /// Yield the current running Coroutine with `Suspended` state
     #[inline]
     pub fn sched() {
+        Coroutine::yield_now(State::Running);
     }
 
     /// Yield the current running Coroutine with `Blocked` state
     #[inline]
     pub fn block() {
+        Coroutine::yield_now(State::Blocked);
+    }
+
+    /// Block on an arbitrary `Future` without a full async runtime.
+    ///
+    /// Polls `f` with a `Waker` that, when woken (possibly from another thread) while this
+    /// Coroutine is `Blocked` or `Cancelling`, pushes its `Handle` onto the ready queue of the
+    /// `Scheduler` that was running it when `await_future` was called, or, with no such
+    /// `Scheduler`, resumes the `Handle` inline on the waking thread. Every time the poll returns `Poll::Pending`, the Coroutine
+    /// transitions to `Blocked` and yields to its parent instead of busy-waiting; once woken
+    /// and resumed it re-polls. This lets `async fn`/combinator code written against the
+    /// `Future` ecosystem be driven from inside a `spawn`ed Coroutine.
+    pub fn await_future<F>(f: F) -> F::Output
+        where F: Future
+    {
+        let handle = Coroutine::current().clone();
+        let waker = waker_for(handle);
+        let mut task_cx = TaskContext::from_waker(&waker);
+
+        let mut f = f;
+        let mut f = unsafe { Pin::new_unchecked(&mut f) };
+        loop {
+            match f.as_mut().poll(&mut task_cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => Coroutine::yield_now(State::Blocked),
+            }
+        }
     }
 
     /// Get a Handle to the current running Coroutine.
@@ -220,25 +608,404 @@ impl Coroutine {
     /// in more than one native thread.
     #[inline]
     pub fn current() -> &'static Handle {
-      &HANDLE
+        CURRENT_COROUTINE.with(|c| {
+            match unsafe { &*c.get() } {
+                &Some(ref handle) => unsafe { transmute::<&Handle, &'static Handle>(handle) },
+                &None => panic!("Coroutine::current() called outside of a running Coroutine"),
+            }
+        })
+    }
+
+    /// Idempotently move this Coroutine into the `Cancelling` state.
+    ///
+    /// A no-op if the Coroutine is already `Cancelling` or has already `Completed`.
+    fn into_cancelling(&self) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Running | State::Blocked => *state = State::Cancelling,
+            State::Cancelling | State::Completed | State::Panicked => {}
+        }
+    }
+
+    /// Set `state`.
+    fn set_state(&self, state: State) {
+        *self.state.lock().unwrap() = state;
     }
 
+    /// Run `f` with cancellation of the current Coroutine suppressed.
+    ///
+    /// A concurrent `Handle::cancel()` made while `f` is running is only recorded; the unwind
+    /// it would otherwise trigger is deferred until `f` returns and the Coroutine reaches its
+    /// next yield point. Use this around a region that hands a pointer to a stack-local buffer
+    /// to an asynchronous party (e.g. a completion-based I/O submission) so the buffer's frame
+    /// cannot be unwound out from under the in-flight operation.
+    pub fn uninterruptible<F, R>(f: F) -> R
+        where F: FnOnce() -> R
+    {
+        let _guard = Coroutine::current().uninterruptible_guard();
+        f()
+    }
 
     /// Get the name of the Coroutine
     #[inline(always)]
     pub fn name(&self) -> Option<&str> {
-None
+        self.name.as_ref().map(|s| &**s)
     }
 
     /// Determines whether the current Coroutine is unwinding because of panic.
     #[inline(always)]
     pub fn panicking(&self) -> bool {
-      false
+        self.unwinding.get()
     }
 
     /// Determines whether the Coroutine is finished
+    ///
+    /// True for both a normal completion and a panicked one; check `state()` for
+    /// `State::Panicked` (or use `Handle::join()`, which surfaces the panic message) to tell
+    /// the two apart.
     #[inline(always)]
     pub fn finished(&self) -> bool {
-        true
+        match *self.state.lock().unwrap() {
+            State::Completed | State::Panicked => true,
+            _ => false,
+        }
+    }
+}
+
+/// The signal a Coroutine's yield carries back to whoever is driving it in a loop, such as a
+/// `Scheduler`: whether to put the `Handle` back on the ready queue, park it off-queue, or
+/// drop it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SchedSignal {
+    /// Still runnable; re-enqueue it.
+    Yielded,
+    /// Parked until something external reschedules it, e.g. via `Scheduler::wake`.
+    Blocked,
+    /// Finished (normally, panicked, or cancelled); drop it.
+    Completed,
+}
+
+impl From<State> for SchedSignal {
+    fn from(state: State) -> SchedSignal {
+        match state {
+            State::Running | State::Cancelling => SchedSignal::Yielded,
+            State::Blocked => SchedSignal::Blocked,
+            State::Completed | State::Panicked => SchedSignal::Completed,
+        }
+    }
+}
+
+/// A cheaply-`Clone`able handle onto a `Scheduler`'s ready queue.
+///
+/// Captured by `Coroutine::await_future` (via `CURRENT_WAKE_SINK`) into the `Waker` it hands to
+/// the polled `Future`, so that when that `Waker` fires later -- possibly from a thread that has
+/// nothing to do with this `Scheduler`, e.g. an I/O completion callback -- the woken `Handle` is
+/// pushed back onto the queue of the `Scheduler` that owns it, rather than resumed inline from
+/// whatever thread happened to call `wake()`.
+#[derive(Clone)]
+struct WakeSink(Arc<Mutex<VecDeque<Handle>>>);
+
+impl WakeSink {
+    fn push(&self, handle: Handle) {
+        self.0.lock().unwrap().push_back(handle);
+    }
+}
+
+/// A round-robin, single-threaded scheduler that drives a ready queue of `Handle`s to
+/// completion.
+///
+/// This reifies the parent/scheduler model described at the top of this module: the
+/// scheduler resumes a Coroutine (procedure I), the Coroutine does some work (procedure II),
+/// and when it yields back (procedure III) the scheduler decides whether to requeue, park, or
+/// drop it based on the `SchedSignal` its resulting `State` maps to, then moves on to the next
+/// Coroutine in the queue.
+pub struct Scheduler {
+    ready: VecDeque<Handle>,
+    sink: WakeSink,
+}
+
+impl Scheduler {
+    /// Create an empty Scheduler.
+    pub fn new() -> Scheduler {
+        Scheduler {
+            ready: VecDeque::new(),
+            sink: WakeSink(Arc::new(Mutex::new(VecDeque::new()))),
+        }
+    }
+
+    /// Spawn a Coroutine and put it on the ready queue.
+    pub fn spawn<F>(&mut self, f: F) -> Handle
+        where F: FnOnce() + Send + 'static
+    {
+        let handle = Coroutine::spawn(f);
+        self.ready.push_back(handle.clone());
+        handle
+    }
+
+    /// Move a parked/blocked Coroutine back onto the ready queue from outside the run loop.
+    pub fn wake(&mut self, handle: Handle) {
+        self.ready.push_back(handle);
+    }
+
+    /// Run every ready Coroutine, round-robin, until the ready queue drains.
+    ///
+    /// A Coroutine that yields `Blocked` falls off the queue (its `Handle` is not dropped,
+    /// just no longer driven) until `wake()` is called with it again, or until a `Waker`
+    /// obtained from an `await_future` call made during one of this Scheduler's own `resume()`s
+    /// fires and pushes it onto `sink` (see `CURRENT_WAKE_SINK`).
+    pub fn run(&mut self) {
+        loop {
+            {
+                let mut woken = self.sink.0.lock().unwrap();
+                self.ready.extend(woken.drain(..));
+            }
+
+            let handle = match self.ready.pop_front() {
+                Some(handle) => handle,
+                None => break,
+            };
+
+            let result = CURRENT_WAKE_SINK.with(|cell| {
+                *cell.borrow_mut() = Some(self.sink.clone());
+                let result = handle.resume();
+                *cell.borrow_mut() = None;
+                result
+            });
+
+            let state = match result {
+                Ok(state) => state,
+                Err(_) => continue,
+            };
+
+            if let SchedSignal::Yielded = SchedSignal::from(state) {
+                self.ready.push_back(handle);
+            }
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
+
+extern "C" fn coroutine_entry<F>(_data: usize) -> !
+    where F: FnOnce() + Send + 'static
+{
+    let handle = Coroutine::current().clone();
+    let coro = unsafe { &mut *handle.0.get() };
+    let f = unsafe { Box::from_raw(coro.entry_data as *mut F) };
+    coro.entry_data = 0;
+
+    Coroutine::main(move || (*f)());
+}
+
+/// Install (once per process) a panic hook that marks the currently running Coroutine as
+/// `unwinding` for the duration of an in-flight application panic.
+///
+/// `panic!` invokes the hook before the stack starts unwinding; `Handle::cancel()`'s own
+/// unwind goes through `panic::resume_unwind` instead, which never calls the hook, so it
+/// can't be confused with a real panic here. This mirrors how the old Rust runtime's task
+/// unwinder flagged a task as "unwinding" for the benefit of `Drop` impls further up the stack.
+fn install_panic_hook() {
+    static INSTALLED: ::std::sync::Once = ::std::sync::Once::new();
+    INSTALLED.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let current = CURRENT_COROUTINE.with(|c| unsafe { (*c.get()).clone() });
+            if let Some(handle) = current {
+                let coro = unsafe { &*handle.0.get() };
+                coro.unwinding.set(true);
+            }
+            default_hook(info);
+        }));
+    });
+}
+
+/// Render a captured panic payload the same way the standard library's default panic message
+/// would, for `Handle::join()`'s `Error::Panicked`.
+fn panic_message(payload: Option<Box<dyn Any + Send>>) -> String {
+    match payload {
+        Some(payload) => {
+            if let Some(s) = payload.downcast_ref::<&'static str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "Box<Any>".to_string()
+            }
+        }
+        None => "Box<Any>".to_string(),
+    }
+}
+
+type WakerPayload = (Handle, Option<WakeSink>);
+
+/// Build a `Waker` that wakes `handle` when fired, used by `Coroutine::await_future`. Besides
+/// the `Handle`, it captures whichever `WakeSink` the currently-running `Scheduler` (if any)
+/// installed for the duration of this resume, so a later fire routes back through that
+/// Scheduler's queue instead of resuming `handle` inline -- see `WakeSink`. The payload is
+/// reference counted so the `Waker` can be cloned and kept alive (and woken from another
+/// thread) independently of the Coroutine's own lifetime.
+fn waker_for(handle: Handle) -> Waker {
+    let sink = CURRENT_WAKE_SINK.with(|cell| cell.borrow().clone());
+    let data = Arc::into_raw(Arc::new((handle, sink) as WakerPayload)) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(data, &WAKER_VTABLE)) }
+}
+
+static WAKER_VTABLE: RawWakerVtable = RawWakerVtable::new(
+    waker_clone,
+    waker_wake,
+    waker_wake_by_ref,
+    waker_drop,
+);
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    let payload = data as *const WakerPayload;
+    Arc::increment_strong_count(payload);
+    RawWaker::new(data, &WAKER_VTABLE)
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    let payload = Arc::from_raw(data as *const WakerPayload);
+    wake_payload(&payload);
+}
+
+unsafe fn waker_wake_by_ref(data: *const ()) {
+    let payload = &*(data as *const WakerPayload);
+    wake_payload(payload);
+}
+
+unsafe fn waker_drop(data: *const ()) {
+    drop(Arc::from_raw(data as *const WakerPayload));
+}
+
+/// Route a firing `Waker`'s payload to whichever Scheduler owns it, if any; otherwise fall back
+/// to resuming the `Handle` inline on whatever thread the wake happened on.
+fn wake_payload(payload: &WakerPayload) {
+    let (handle, sink) = payload;
+    // `Cancelling` is included so a Coroutine cancelled while parked in `await_future` still
+    // gets driven by its waker (into its unwind) instead of staying parked forever.
+    match handle.state() {
+        State::Blocked | State::Cancelling => {
+            match sink {
+                Some(sink) => sink.push(handle.clone()),
+                None => { let _ = handle.resume(); }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test]
+    fn join_drains_a_scoped_child_that_blocks_before_it_finishes() {
+        // Regression test: `spawn_scoped`/`spawn_child` children are never registered on any
+        // `Scheduler`'s ready queue, so `Handle::join()` (via `Coroutine::main`'s children-drain
+        // loop) is the only thing that can ever resume a child like this one. `join()` must poll
+        // it to completion itself instead of parking and waiting for some other driver that
+        // doesn't exist.
+        let child_progress = Arc::new(AtomicUsize::new(0));
+        let progress = child_progress.clone();
+
+        let parent = Coroutine::spawn(move || {
+            let _child = Coroutine::spawn_scoped(move || {
+                for _ in 0..3 {
+                    progress.fetch_add(1, Ordering::SeqCst);
+                    Coroutine::block();
+                }
+            }, Options::default());
+        });
+
+        assert_eq!(parent.join().unwrap(), State::Completed);
+        assert_eq!(child_progress.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn cancel_before_first_resume_skips_the_body() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran2 = ran.clone();
+
+        let handle = Coroutine::spawn(move || {
+            ran2.store(true, Ordering::SeqCst);
+        });
+        handle.cancel();
+
+        assert_eq!(handle.join().unwrap(), State::Completed);
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn cancel_while_blocked_unwinds_instead_of_hanging() {
+        let handle = Coroutine::spawn(|| {
+            loop {
+                Coroutine::block();
+            }
+        });
+
+        // Drive it once so it parks at its first `block()`.
+        let _ = handle.resume();
+        assert_eq!(handle.state(), State::Blocked);
+
+        handle.cancel();
+
+        assert_eq!(handle.join().unwrap(), State::Completed);
+    }
+
+    #[test]
+    fn scoped_child_panic_is_isolated_from_its_parent() {
+        let child_ran = Arc::new(AtomicBool::new(false));
+        let flag = child_ran.clone();
+
+        let parent = Coroutine::spawn(move || {
+            let _child = Coroutine::spawn_scoped(move || {
+                flag.store(true, Ordering::SeqCst);
+                panic!("boom");
+            }, Options::default());
+        });
+
+        // The parent completes normally: a scoped child's panic is captured on the child's own
+        // Handle, not propagated up through the parent that joined it while draining.
+        assert_eq!(parent.join().unwrap(), State::Completed);
+        assert!(child_ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panicking_coroutine_reports_its_message_through_join() {
+        let handle = Coroutine::spawn(|| {
+            panic!("boom");
+        });
+
+        match handle.join() {
+            Err(Error::Panicked(msg)) => assert!(msg.contains("boom")),
+            other => panic!("expected Err(Error::Panicked(_)), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scheduler_wake_resumes_a_manually_parked_coroutine() {
+        let mut sched = Scheduler::new();
+        let progressed = Arc::new(AtomicBool::new(false));
+        let flag = progressed.clone();
+
+        let handle = sched.spawn(move || {
+            Coroutine::block();
+            flag.store(true, Ordering::SeqCst);
+        });
+        sched.run();
+
+        assert_eq!(handle.state(), State::Blocked);
+        assert!(!progressed.load(Ordering::SeqCst));
+
+        sched.wake(handle.clone());
+        sched.run();
+
+        assert!(progressed.load(Ordering::SeqCst));
+        assert_eq!(handle.state(), State::Completed);
     }
 }